@@ -26,6 +26,7 @@ extern crate rustc;
 extern crate rustc_driver;
 extern crate syntax;
 
+use rustc::hir::def::Res;
 use rustc::hir::def_id::DefId;
 use rustc::hir::intravisit as visit;
 use rustc::hir::{self, ExprKind, HirId};
@@ -49,6 +50,14 @@ fn registrar(reg: &mut Registry) {
     reg.lint_store.register_lints(&[&UNROOTED_MUST_ROOT]);
     reg.lint_store
         .register_late_pass(move || Box::new(UnrootedPass::new(symbols.clone())));
+    let symbols = Symbols::new();
+    reg.lint_store.register_lints(&[&INHERITANCE_INTEGRITY]);
+    reg.lint_store
+        .register_late_pass(move || Box::new(InheritancePass::new(symbols.clone())));
+    let symbols = Symbols::new();
+    reg.lint_store.register_lints(&[&TRANSMUTE_TYPE]);
+    reg.lint_store
+        .register_late_pass(move || Box::new(TransmutePass::new(symbols.clone())));
 }
 
 declare_lint!(
@@ -73,7 +82,12 @@ declare_lint!(
 ///
 /// Structs which have their own mechanism of rooting their unrooted contents (e.g. `ScriptThread`)
 /// can be marked as `#[allow(unrooted_must_root)]`. Smart pointers which root their interior type
-/// can be marked as `#[unrooted_must_root_lint::allow_unrooted_interior]`
+/// can be marked as `#[unrooted_must_root_lint::allow_unrooted_interior]`. Containers which root
+/// their interior for the purposes of assignment and call checking (e.g. `DomRefCell`,
+/// `RootedVec`) can be marked as `#[unrooted_must_root_lint::rooted_wrapper]`. Types which
+/// behave like a borrow of their interior (e.g. `Ref`, `RefMut`, hashmap iterators) can be
+/// marked as `#[unrooted_must_root_lint::transparent_ref]` so that their contents aren't
+/// treated as owned/unrooted
 pub(crate) struct UnrootedPass {
     symbols: Symbols,
 }
@@ -120,7 +134,8 @@ fn is_unrooted_ty(sym: &Symbols, cx: &LateContext, ty: &ty::TyS, in_new_function
                     } else {
                         true
                     }
-                } else if match_def_path(cx, did.did, &[sym::core, sym.cell, sym.Ref]) ||
+                } else if has_attr(did.did, sym.transparent_ref) ||
+                    match_def_path(cx, did.did, &[sym::core, sym.cell, sym.Ref]) ||
                     match_def_path(cx, did.did, &[sym::core, sym.cell, sym.RefMut]) ||
                     match_def_path(cx, did.did, &[sym::core, sym.slice, sym.Iter]) ||
                     match_def_path(cx, did.did, &[sym::core, sym.slice, sym.IterMut]) ||
@@ -162,7 +177,9 @@ fn is_unrooted_ty(sym: &Symbols, cx: &LateContext, ty: &ty::TyS, in_new_function
                         &[sym::std, sym.collections, sym.hash, sym.set, sym.Iter],
                     )
                 {
-                    // Structures which are semantically similar to an &ptr.
+                    // Structures which are semantically similar to an &ptr, either because
+                    // they're built into std or because they carry
+                    // `#[unrooted_must_root_lint::transparent_ref]`.
                     false
                 } else if did.is_box() && in_new_function {
                     // box in new() is okay
@@ -270,19 +287,86 @@ impl<'a, 'tcx> LateLintPass<'a, 'tcx> for UnrootedPass {
             }
         }
 
+        if body.generator_kind.is_some() {
+            check_generator_interior(&self.symbols, cx, body);
+        }
+
         let mut visitor = FnDefVisitor {
             symbols: &self.symbols,
             cx: cx,
             in_new_function: in_new_function,
+            in_rooted_place: false,
         };
         visit::walk_expr(&mut visitor, &body.value);
     }
 }
 
+/// Checks an `async fn`/generator body for unrooted values that are captured by the generator
+/// or that live across a `.await`/`yield` suspension point. Such values are stored in the
+/// generator state machine on the heap, where a GC pass can invalidate them without the
+/// stack-rooting machinery ever seeing them.
+fn check_generator_interior(sym: &Symbols, cx: &LateContext, body: &hir::Body) {
+    let generator_ty = cx.tables.expr_ty(&body.value);
+    let (substs, _) = match generator_ty.kind {
+        ty::Generator(_, substs, movability) => (substs, movability),
+        _ => return,
+    };
+    let substs = substs.as_generator();
+
+    for ty in substs.upvar_tys() {
+        if is_unrooted_ty(sym, cx, ty, false) {
+            cx.span_lint(
+                UNROOTED_MUST_ROOT,
+                body.value.span,
+                "Type captured by an async fn/generator must be rooted",
+            )
+        }
+    }
+
+    if let ty::GeneratorWitness(witness_tys) = substs.witness().kind {
+        for ty in witness_tys.skip_binder() {
+            if is_unrooted_ty(sym, cx, ty, false) {
+                cx.span_lint(
+                    UNROOTED_MUST_ROOT,
+                    body.value.span,
+                    "Type held across a yield point must be rooted",
+                )
+            }
+        }
+    }
+}
+
+/// Walks a place expression's receiver chain (`expr.field`, `*expr`, `expr[i]`,
+/// `expr.method_call()`) looking for an ancestor whose type carries
+/// `#[unrooted_must_root_lint::rooted_wrapper]`. Containers like `DomRefCell` and `RootedVec`
+/// root their interior, so a place read out of or written through one of them (e.g.
+/// `*self.cell.borrow_mut()`) should not be treated as unrooted by the assignment/call checks
+/// below, even though the place's own type is just the unwrapped interior.
+fn place_has_rooted_wrapper_ancestor(sym: &Symbols, cx: &LateContext, expr: &hir::Expr) -> bool {
+    if let ty::Adt(did, _) = cx.tables.expr_ty(expr).kind {
+        if has_lint_attr(sym, &cx.tcx.get_attrs(did.did), sym.rooted_wrapper) {
+            return true;
+        }
+    }
+    match expr.kind {
+        ExprKind::Field(ref base, _) |
+        ExprKind::Unary(hir::UnOp::UnDeref, ref base) |
+        ExprKind::Index(ref base, _) => place_has_rooted_wrapper_ancestor(sym, cx, base),
+        ExprKind::MethodCall(_, _, ref args) => args
+            .first()
+            .map_or(false, |receiver| place_has_rooted_wrapper_ancestor(sym, cx, receiver)),
+        _ => false,
+    }
+}
+
 struct FnDefVisitor<'a, 'b: 'a, 'tcx: 'a + 'b> {
     symbols: &'a Symbols,
     cx: &'a LateContext<'b, 'tcx>,
     in_new_function: bool,
+    // Set while walking both sides of an assignment whose LHS place reads through a
+    // `#[unrooted_must_root_lint::rooted_wrapper]` container, so nested constructor calls in
+    // that assignment aren't independently flagged by the call check below.
+    in_rooted_place: bool,
 }
 
 impl<'a, 'b, 'tcx> visit::Visitor<'tcx> for FnDefVisitor<'a, 'b, 'tcx> {
@@ -303,17 +387,27 @@ impl<'a, 'b, 'tcx> visit::Visitor<'tcx> for FnDefVisitor<'a, 'b, 'tcx> {
         match expr.kind {
             // Trait casts from #[unrooted_must_root_lint::must_root] types are not allowed
             ExprKind::Cast(ref subexpr, _) => require_rooted(cx, self.in_new_function, &*subexpr),
-            // This catches assignments... the main point of this would be to catch mutable
+            // This catches assignments; the main point of this is to catch mutable
             // references to `JS<T>`.
-            // FIXME: Enable this? Triggers on certain kinds of uses of DomRefCell.
-            // hir::ExprAssign(_, ref rhs) => require_rooted(cx, self.in_new_function, &*rhs),
+            ExprKind::Assign(ref lhs, ref rhs) => {
+                let in_rooted_place = place_has_rooted_wrapper_ancestor(&self.symbols, cx, lhs);
+                if !in_rooted_place {
+                    require_rooted(cx, self.in_new_function, &*rhs)
+                }
+                let outer = self.in_rooted_place;
+                self.in_rooted_place = outer || in_rooted_place;
+                visit::walk_expr(self, expr);
+                self.in_rooted_place = outer;
+                return;
+            },
             // This catches calls; basically, this enforces the constraint that only constructors
             // can call other constructors.
-            // FIXME: Enable this? Currently triggers with constructs involving DomRefCell, and
-            // constructs like Vec<JS<T>> and RootedVec<JS<T>>.
-            // hir::ExprCall(..) if !self.in_new_function => {
-            //     require_rooted(cx, self.in_new_function, expr);
-            // }
+            ExprKind::Call(..) if !self.in_new_function && !self.in_rooted_place => {
+                // A call producing a `rooted_wrapper` container is rooted by that container.
+                if !place_has_rooted_wrapper_ancestor(&self.symbols, cx, expr) {
+                    require_rooted(cx, self.in_new_function, expr);
+                }
+            },
             _ => {
                 // TODO(pcwalton): Check generics with a whitelist of allowed generics.
             },
@@ -354,6 +448,190 @@ impl<'a, 'b, 'tcx> visit::Visitor<'tcx> for FnDefVisitor<'a, 'b, 'tcx> {
     }
 }
 
+declare_lint!(
+    INHERITANCE_INTEGRITY,
+    Deny,
+    "Warn and report unsafe DOM inheritance"
+);
+
+/// Lint for ensuring proper DOM inheritance
+///
+/// This lint (disable with `-A inheritance-integrity`/`#[allow(inheritance_integrity)]`) ensures that
+/// the first field of a `#[dom_struct]` struct is a `#[dom_struct]` type or `Reflector` itself, and
+/// that no other field is a DOM struct.
+///
+/// This is necessary for the DOM struct's `Reflector` to be found via simple pointer casts, which
+/// is what the JS reflection machinery and the `Castable` trait rely on.
+///
+/// This pass keys off `#[unrooted_must_root_lint::dom_struct]`, which the `#[dom_struct]`
+/// expansion leaves on the struct alongside `#[unrooted_must_root_lint::must_root]`. It must
+/// *not* key off `must_root` alone: that attribute is also applied directly to rooting
+/// primitives like `JS<T>`/`Dom<T>`/`LayoutDom<T>`, which are not reflector-based DOM structs
+/// and have no business being checked for inheritance shape.
+pub(crate) struct InheritancePass {
+    symbols: Symbols,
+}
+
+impl InheritancePass {
+    pub fn new(symbols: Symbols) -> InheritancePass {
+        InheritancePass { symbols }
+    }
+}
+
+impl LintPass for InheritancePass {
+    fn name(&self) -> &'static str {
+        "ServoInheritancePass"
+    }
+}
+
+impl<'a, 'tcx> LateLintPass<'a, 'tcx> for InheritancePass {
+    /// All structs with #[dom_struct] must have the first field be
+    /// a DOM struct or the Reflector itself.
+    fn check_item(&mut self, cx: &LateContext<'a, 'tcx>, item: &'tcx hir::Item) {
+        let is_dom_struct = has_lint_attr(&self.symbols, &item.attrs, self.symbols.dom_struct);
+        if !is_dom_struct {
+            return;
+        }
+        if let hir::ItemKind::Struct(def, ..) = &item.kind {
+            for (index, ref field) in def.fields().iter().enumerate() {
+                let def_id = cx.tcx.hir().local_def_id(field.hir_id);
+                let ty = cx.tcx.type_of(def_id);
+                if let ty::Adt(did, _) = ty.kind {
+                    let field_attrs = cx.tcx.get_attrs(did.did);
+                    let is_dom_struct =
+                        has_lint_attr(&self.symbols, &field_attrs, self.symbols.dom_struct);
+                    let is_reflector = match_def_path(
+                        cx,
+                        did.did,
+                        &[
+                            self.symbols.script,
+                            self.symbols.dom,
+                            self.symbols.bindings,
+                            self.symbols.reflector,
+                            self.symbols.Reflector,
+                        ],
+                    );
+                    if index == 0 {
+                        if !is_dom_struct && !is_reflector {
+                            cx.span_lint(
+                                INHERITANCE_INTEGRITY,
+                                field.span,
+                                "The first field of a DOM struct must be the reflector or \
+                                 another DOM struct",
+                            )
+                        }
+                    } else if is_dom_struct {
+                        cx.span_lint(
+                            INHERITANCE_INTEGRITY,
+                            field.span,
+                            "Bad inheritance! DOM struct inheritance must only occur \
+                             in the first field of a DOM struct",
+                        )
+                    }
+                } else if index == 0 {
+                    cx.span_lint(
+                        INHERITANCE_INTEGRITY,
+                        field.span,
+                        "The first field of a DOM struct must be the reflector or \
+                         another DOM struct",
+                    )
+                }
+            }
+        }
+    }
+}
+
+declare_lint!(
+    TRANSMUTE_TYPE,
+    Deny,
+    "Warn and report unsafe transmutes of DOM/GC-managed types"
+);
+
+/// Lint for ensuring safe usage of `mem::transmute`
+///
+/// This lint (disable with `-A transmute-type`/`#[allow(transmute_type)]`) ensures that
+/// `core::mem::transmute`/`transmute_copy` is never used to produce or consume an
+/// `#[unrooted_must_root_lint::must_root]` value.
+///
+/// Transmuting *out of* such a value defeats the stack-rooting guarantee that `UnrootedPass`
+/// provides; transmuting *into* one fabricates an unrooted pointer the GC never learns about.
+pub(crate) struct TransmutePass {
+    symbols: Symbols,
+}
+
+impl TransmutePass {
+    pub fn new(symbols: Symbols) -> TransmutePass {
+        TransmutePass { symbols }
+    }
+}
+
+impl LintPass for TransmutePass {
+    fn name(&self) -> &'static str {
+        "ServoTransmutePass"
+    }
+}
+
+impl<'a, 'tcx> LateLintPass<'a, 'tcx> for TransmutePass {
+    /// Calls to `mem::transmute`/`mem::transmute_copy` must not produce or consume an
+    /// unrooted DOM/GC-managed type.
+    fn check_expr(&mut self, cx: &LateContext<'a, 'tcx>, expr: &'tcx hir::Expr) {
+        let (func, args) = match expr.kind {
+            ExprKind::Call(ref func, ref args) => (func, args),
+            _ => return,
+        };
+
+        let did = match func.kind {
+            ExprKind::Path(ref qpath) => match cx.tables.qpath_res(qpath, func.hir_id) {
+                Res::Def(_, did) => did,
+                _ => return,
+            },
+            _ => return,
+        };
+
+        let is_transmute =
+            match_def_path(cx, did, &[sym::core, self.symbols.mem, self.symbols.transmute]);
+        let is_transmute_copy =
+            match_def_path(cx, did, &[sym::core, self.symbols.mem, self.symbols.transmute_copy]);
+        if !(is_transmute || is_transmute_copy) || args.len() != 1 {
+            return;
+        }
+
+        let arg_ty = cx.tables.expr_ty(&args[0]);
+        // `transmute_copy<T, U>(src: &T) -> U` always takes its argument by reference; strip
+        // that outer reference so the check below inspects `T` itself, not `&T`.
+        let from_ty = if is_transmute_copy {
+            match arg_ty.kind {
+                ty::Ref(_, ty, _) => ty,
+                _ => arg_ty,
+            }
+        } else {
+            arg_ty
+        };
+        let to_ty = cx.tables.expr_ty(expr);
+
+        if is_unrooted_ty(&self.symbols, cx, from_ty, false) ||
+            is_unrooted_ty(&self.symbols, cx, to_ty, false)
+        {
+            cx.span_lint(
+                TRANSMUTE_TYPE,
+                expr.span,
+                "Transmuting to/from a DOM-managed type is unsafe, it bypasses the \
+                 stack-rooting guarantee unrooted_must_root relies on",
+            )
+        } else if is_transmute &&
+            matches!(from_ty.kind, ty::Ref(..)) != matches!(to_ty.kind, ty::Ref(..))
+        {
+            // `transmute_copy` legitimately goes from `&T` to an owned `U`; only flag this
+            // mismatch for plain `transmute`, where both sides should agree.
+            cx.span_lint(
+                TRANSMUTE_TYPE,
+                expr.span,
+                "Transmuting between a reference and an owned value is unsafe",
+            )
+        }
+    }
+}
+
 /// check if a DefId's path matches the given absolute type path
 /// usage e.g. with
 /// `match_def_path(cx, id, &["core", "option", "Option"])`
@@ -407,6 +685,14 @@ symbols! {
     allow_unrooted_interior
     allow_unrooted_in_rc
     must_root
+    rooted_wrapper
+    transparent_ref
+    dom_struct
+    Reflector
+    script
+    dom
+    bindings
+    reflector
     alloc
     rc
     Rc
@@ -423,4 +709,7 @@ symbols! {
     Entry
     OccupiedEntry
     VacantEntry
+    mem
+    transmute
+    transmute_copy
 }